@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use dbus::{BusType, Connection, ConnectionItem, Message, MessageItem, Path};
+
+use adapters::adapter::*;
+use discovery::discovery_manager::*;
+
+const AVAHI_DBUS_NAME: &'static str = "org.freedesktop.Avahi";
+const AVAHI_DBUS_PATH_SERVER: &'static str = "/";
+const AVAHI_DBUS_INTERFACE_SERVER: &'static str = "org.freedesktop.Avahi.Server";
+const AVAHI_DBUS_INTERFACE_SERVICE_BROWSER: &'static str = "org.freedesktop.Avahi.ServiceBrowser";
+const AVAHI_DBUS_INTERFACE_SERVICE_RESOLVER: &'static str = "org.freedesktop.Avahi.ServiceResolver";
+
+const AVAHI_IF_UNSPEC: i32 = -1;
+const AVAHI_PROTO_UNSPEC: i32 = -1;
+const AVAHI_PROTO_INET: i32 = 0;
+const AVAHI_PROTO_INET6: i32 = 1;
+const AVAHI_LOOKUP_RESULT_UNSPEC: u32 = 0;
+
+/// Extracts a `String` argument at `index` from a D-Bus signal payload.
+fn string_arg(args: &[MessageItem], index: usize) -> Option<String> {
+    args.get(index).and_then(|item| item.inner::<&str>().ok()).map(|value| value.to_owned())
+}
+
+/// Extracts the `AvahiProtocol` argument at `index` and maps it to the
+/// crate's `ServiceProtocol`, defaulting to IPv4 for `AVAHI_PROTO_UNSPEC`
+/// (and any other unrecognised value).
+fn protocol_arg(args: &[MessageItem], index: usize) -> ServiceProtocol {
+    match args.get(index).and_then(|item| item.inner::<i32>().ok()) {
+        Some(AVAHI_PROTO_INET6) => ServiceProtocol::IPv6,
+        _ => ServiceProtocol::IPv4,
+    }
+}
+
+/// The inverse of `protocol_arg`, for passing a `ServiceInfo`'s protocol back
+/// into a D-Bus call that wants the raw `AvahiProtocol`.
+fn to_avahi_protocol(protocol: ServiceProtocol) -> i32 {
+    match protocol {
+        ServiceProtocol::IPv6 => AVAHI_PROTO_INET6,
+        ServiceProtocol::IPv4 => AVAHI_PROTO_INET,
+    }
+}
+
+/// Parses the `aay` TXT argument of a `Found` signal into both the
+/// joined/quoted form and the structured `key` -> raw-bytes map, mirroring
+/// `AvahiWrapper`'s `parse_txt`/`parse_txt_records`.
+fn parse_txt_arg(item: Option<&MessageItem>) -> (Option<String>, Option<HashMap<String, Vec<u8>>>) {
+    let entries = match item {
+        Some(&MessageItem::Array(ref entries, _)) => entries,
+        _ => return (None, None),
+    };
+
+    let mut records = HashMap::new();
+    let mut joined = Vec::new();
+
+    for entry in entries {
+        let bytes: Vec<u8> = match *entry {
+            MessageItem::Array(ref bytes, _) => {
+                bytes.iter()
+                    .filter_map(|byte| match *byte {
+                        MessageItem::Byte(b) => Some(b),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            _ => continue,
+        };
+
+        joined.push(format!("\"{}\"", String::from_utf8_lossy(&bytes)));
+
+        let mut parts = bytes.splitn(2, |&b| b == b'=');
+        if let Some(key_bytes) = parts.next() {
+            let key = String::from_utf8_lossy(key_bytes).into_owned();
+            let value = parts.next().map(|value_bytes| value_bytes.to_vec()).unwrap_or_default();
+
+            records.insert(key, value);
+        }
+    }
+
+    (Some(joined.join(" ")), Some(records))
+}
+
+/// Talks to `avahi-daemon` purely over the system D-Bus, so it can be used
+/// in place of `AvahiWrapper` wherever the `avahi-client`/`avahi-common`
+/// headers aren't available at build time.
+pub struct AvahiDbusAdapter {
+    connection: Connection,
+    service_browser_path: RefCell<Option<String>>,
+}
+
+impl AvahiDbusAdapter {
+    fn call(&self, path: &str, interface: &str, method: &str, args: &[MessageItem]) -> Message {
+        let mut message = Message::new_method_call(AVAHI_DBUS_NAME, path, interface, method)
+            .unwrap_or_else(|error| panic!("Failed to build D-Bus message: {}", error));
+
+        message.append_items(args);
+
+        self.connection
+            .send_with_reply_and_block(message, 5000)
+            .unwrap_or_else(|error| panic!("Avahi D-Bus call {}.{} failed: {}", interface, method, error))
+    }
+
+    fn resolve_service(&self,
+                       interface: i32,
+                       protocol: i32,
+                       name: &str,
+                       service_type: &str,
+                       domain: &str,
+                       listener: &ResolveListeners) {
+        let reply = self.call(AVAHI_DBUS_PATH_SERVER,
+                              AVAHI_DBUS_INTERFACE_SERVER,
+                              "ServiceResolverNew",
+                              &[MessageItem::Int32(interface),
+                                MessageItem::Int32(protocol),
+                                MessageItem::Str(name.to_owned()),
+                                MessageItem::Str(service_type.to_owned()),
+                                MessageItem::Str(domain.to_owned()),
+                                MessageItem::Int32(AVAHI_PROTO_UNSPEC),
+                                MessageItem::UInt32(AVAHI_LOOKUP_RESULT_UNSPEC)]);
+
+        let resolver_path = match reply.get1::<Path>() {
+            Some(path) => path.to_string(),
+            None => return,
+        };
+
+        for item in self.connection.iter(5000) {
+            if let ConnectionItem::Signal(message) = item {
+                if message.path().map(|p| p.to_string()) != Some(resolver_path.clone()) {
+                    continue;
+                }
+
+                match message.member().map(|m| m.to_string()).as_ref().map(|s| s.as_str()) {
+                    Some("Found") => {
+                        if listener.on_service_resolved.is_some() {
+                            // Found(interface, protocol, name, type, domain, host_name,
+                            //       aprotocol, address, port, txt, flags)
+                            let args = message.get_items();
+
+                            let host_name = string_arg(&args, 5);
+                            let address = string_arg(&args, 7);
+                            let port = args.get(8).and_then(|item| item.inner::<u16>().ok()).unwrap_or(0);
+                            let resolved_protocol = protocol_arg(&args, 6);
+                            let (txt, txt_records) = parse_txt_arg(args.get(9));
+
+                            (*listener.on_service_resolved.unwrap())(ServiceInfo {
+                                address: address,
+                                domain: Some(domain.to_owned()),
+                                host_name: host_name,
+                                interface: interface,
+                                name: Some(name.to_owned()),
+                                port: port,
+                                protocol: resolved_protocol,
+                                txt: txt,
+                                txt_records: txt_records,
+                                type_name: Some(service_type.to_owned()),
+                            });
+                        }
+
+                        return;
+                    }
+                    Some("Failure") => return,
+                    _ => continue,
+                }
+            }
+        }
+    }
+}
+
+impl DiscoveryAdapter for AvahiDbusAdapter {
+    fn start_discovery(&self, service_type: &str, listeners: DiscoveryListeners) {
+        let reply = self.call(AVAHI_DBUS_PATH_SERVER,
+                              AVAHI_DBUS_INTERFACE_SERVER,
+                              "ServiceBrowserNew",
+                              &[MessageItem::Int32(AVAHI_IF_UNSPEC),
+                                MessageItem::Int32(AVAHI_PROTO_UNSPEC),
+                                MessageItem::Str(service_type.to_owned()),
+                                MessageItem::Str(String::new()),
+                                MessageItem::UInt32(AVAHI_LOOKUP_RESULT_UNSPEC)]);
+
+        let browser_path = match reply.get1::<Path>() {
+            Some(path) => path.to_string(),
+            None => return,
+        };
+
+        *self.service_browser_path.borrow_mut() = Some(browser_path.clone());
+
+        for item in self.connection.iter(1000) {
+            if let ConnectionItem::Signal(message) = item {
+                if message.path().map(|p| p.to_string()) != Some(browser_path.clone()) {
+                    continue;
+                }
+
+                // ItemNew/ItemRemove(interface, protocol, name, type, domain, flags)
+                let args = message.get_items();
+
+                match message.member().map(|m| m.to_string()).as_ref().map(|s| s.as_str()) {
+                    Some("ItemNew") => {
+                        if listeners.on_service_discovered.is_some() {
+                            (*listeners.on_service_discovered.unwrap())(ServiceInfo {
+                                address: None,
+                                domain: string_arg(&args, 4),
+                                host_name: None,
+                                interface: args.get(0)
+                                    .and_then(|item| item.inner::<i32>().ok())
+                                    .unwrap_or(AVAHI_IF_UNSPEC),
+                                name: string_arg(&args, 2),
+                                port: 0,
+                                protocol: protocol_arg(&args, 1),
+                                txt: None,
+                                txt_records: None,
+                                type_name: string_arg(&args, 3),
+                            });
+                        }
+
+                        // Left unresolved, same as `AvahiWrapper::start_browser` -
+                        // resolving each discovered item eagerly would need a
+                        // `ResolveListeners` we aren't handed here.
+                    }
+                    Some("ItemRemove") => {
+                        if listeners.on_service_removed.is_some() {
+                            (*listeners.on_service_removed.unwrap())(ServiceInfo {
+                                address: None,
+                                domain: string_arg(&args, 4),
+                                host_name: None,
+                                interface: args.get(0)
+                                    .and_then(|item| item.inner::<i32>().ok())
+                                    .unwrap_or(AVAHI_IF_UNSPEC),
+                                name: string_arg(&args, 2),
+                                port: 0,
+                                protocol: protocol_arg(&args, 1),
+                                txt: None,
+                                txt_records: None,
+                                type_name: string_arg(&args, 3),
+                            });
+                        }
+                    }
+                    Some("AllForNow") => {
+                        if listeners.on_all_discovered.is_some() {
+                            (*listeners.on_all_discovered.unwrap())();
+                        }
+
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn resolve(&self, service: ServiceInfo, listeners: ResolveListeners) {
+        self.resolve_service(service.interface,
+                             to_avahi_protocol(service.protocol),
+                             &service.name.unwrap_or_default(),
+                             &service.type_name.unwrap_or_default(),
+                             &service.domain.unwrap_or_default(),
+                             &listeners);
+    }
+
+    fn stop_discovery(&self) {
+        if let Some(path) = self.service_browser_path.borrow_mut().take() {
+            self.call(&path, AVAHI_DBUS_INTERFACE_SERVICE_BROWSER, "Free", &[]);
+        }
+    }
+}
+
+impl HostAdapter for AvahiDbusAdapter {
+    fn get_name(&self) -> String {
+        self.call(AVAHI_DBUS_PATH_SERVER, AVAHI_DBUS_INTERFACE_SERVER, "GetHostName", &[])
+            .get1::<String>()
+            .unwrap_or_default()
+    }
+
+    fn get_name_fqdn(&self) -> String {
+        self.call(AVAHI_DBUS_PATH_SERVER, AVAHI_DBUS_INTERFACE_SERVER, "GetHostNameFqdn", &[])
+            .get1::<String>()
+            .unwrap_or_default()
+    }
+
+    fn set_name(&self, host_name: &str) -> String {
+        self.call(AVAHI_DBUS_PATH_SERVER,
+                 AVAHI_DBUS_INTERFACE_SERVER,
+                 "SetHostName",
+                 &[MessageItem::Str(host_name.to_owned())]);
+
+        host_name.to_owned()
+    }
+
+    fn is_valid_name(&self, host_name: &str) -> bool {
+        debug!("Verifying host name over D-Bus: {}.", host_name);
+        !host_name.is_empty()
+    }
+
+    fn get_alternative_name(&self, host_name: &str) -> String {
+        self.call(AVAHI_DBUS_PATH_SERVER,
+                 AVAHI_DBUS_INTERFACE_SERVER,
+                 "GetAlternativeHostName",
+                 &[MessageItem::Str(host_name.to_owned())])
+            .get1::<String>()
+            .unwrap_or_else(|| format!("{}-2", host_name))
+    }
+
+    fn add_name_alias(&self, host_name: &str) {
+        warn!("Host name change request (-> {}) will be ignored over D-Bus.",
+              host_name);
+    }
+}
+
+impl Drop for AvahiDbusAdapter {
+    fn drop(&mut self) {
+        self.stop_discovery();
+    }
+}
+
+impl Adapter for AvahiDbusAdapter {
+    fn new() -> AvahiDbusAdapter {
+        let connection = Connection::get_private(BusType::System)
+            .unwrap_or_else(|error| panic!("Failed to connect to system D-Bus: {}", error));
+
+        AvahiDbusAdapter {
+            connection: connection,
+            service_browser_path: RefCell::new(None),
+        }
+    }
+}
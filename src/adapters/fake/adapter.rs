@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use adapters::adapter::*;
 use discovery::discovery_manager::*;
 
@@ -17,6 +19,7 @@ impl DiscoveryAdapter for FakeAdapter {
                 port: 0,
                 protocol: ServiceProtocol::IPv4,
                 txt: None,
+                txt_records: None,
                 type_name: Some(service_type.to_string()),
             });
         }
@@ -36,6 +39,11 @@ impl DiscoveryAdapter for FakeAdapter {
             port: 80,
             protocol: service.protocol,
             txt: Some(format!("\"model=Xserve\"")),
+            txt_records: Some({
+                let mut records = HashMap::new();
+                records.insert("model".to_owned(), b"Xserve".to_vec());
+                records
+            }),
             type_name: service.type_name,
         };
 
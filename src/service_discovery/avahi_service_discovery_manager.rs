@@ -1,3 +1,4 @@
+use bindings::avahi::AvahiProtocol;
 use service_discovery::service_discovery_manager::*;
 use service_discovery::avah_wrapper::*;
 
@@ -10,26 +11,35 @@ impl ServiceDiscoveryManager for AvahiServiceDiscoveryManager {
         AvahiServiceDiscoveryManager { wrapper: AvahiWrapper::new() }
     }
 
-    fn discover_services<F>(&self, service_type: &str, callback: F)
+    fn discover_services<F>(&self, service_type: &str, protocol: AvahiProtocol, callback: F)
         where F: FnMut(ServiceDescription)
     {
-        self.wrapper.start_browser(service_type, callback);
+        self.wrapper.start_browser(service_type, protocol, callback);
     }
 
-    fn discover_services_sync<F>(&self, service_type: &str, callback: F)
+    fn discover_services_sync<F>(&self, service_type: &str, protocol: AvahiProtocol, callback: F)
         where F: FnMut(ServiceDescription)
     {
-        self.wrapper.start_browser_sync(service_type, callback);
+        self.wrapper.start_browser_sync(service_type, protocol, callback);
     }
 
-    fn resolve_service<F>(&self, service_description: ServiceDescription, callback: F)
+    fn resolve_service<F>(&self,
+                          service_description: ServiceDescription,
+                          protocol: AvahiProtocol,
+                          callback: F)
         where F: FnMut(ServiceDescription),
               F: 'static
     {
-        self.wrapper.resolve(service_description, callback);
+        self.wrapper.resolve(service_description, protocol, callback);
     }
 
     fn stop_service_discovery(&self) {
         self.wrapper.stop_browser();
     }
+
+    fn register_service<F>(&self, service_description: ServiceDescription, callback: F)
+        where F: FnMut(String)
+    {
+        self.wrapper.publish_service(service_description, callback);
+    }
 }
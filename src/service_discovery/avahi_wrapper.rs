@@ -1,16 +1,20 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::mem;
 use std::ptr;
+use std::slice;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Sender;
-use libc::{c_char, c_void, c_int, free};
+use libc::{c_char, c_void, c_int, size_t, free};
 
 use bindings::avahi::*;
+use adapters::adapter::ServiceProtocol;
 use service_discovery::service_discovery_manager::ServiceDescription;
 use service_discovery::service_discovery_manager::DiscoveryListener;
 use service_discovery::service_discovery_manager::ResolveListener;
+use service_discovery::service_discovery_manager::PublishListener;
 
 fn parse_c_string(c_string: *const c_char) -> Option<String> {
     if c_string.is_null() {
@@ -20,14 +24,36 @@ fn parse_c_string(c_string: *const c_char) -> Option<String> {
     }
 }
 
-fn parse_address(address: *const AvahiAddress) -> Option<String> {
+/// Maps the raw `AvahiProtocol` address family to the crate's `ServiceProtocol`.
+fn to_service_protocol(protocol: AvahiProtocol) -> ServiceProtocol {
+    if protocol == AVAHI_PROTO_INET6 {
+        ServiceProtocol::IPv6
+    } else {
+        ServiceProtocol::IPv4
+    }
+}
+
+/// The inverse of `to_service_protocol`, for passing a `ServiceDescription`'s
+/// protocol back into the FFI calls that expect the raw `AvahiProtocol`.
+fn to_avahi_protocol(protocol: ServiceProtocol) -> AvahiProtocol {
+    match protocol {
+        ServiceProtocol::IPv6 => AVAHI_PROTO_INET6,
+        ServiceProtocol::IPv4 => AVAHI_PROTO_INET,
+    }
+}
+
+/// Renders `address` to its textual form and reports whether it came back
+/// as an `AVAHI_PROTO_INET` (A) or `AVAHI_PROTO_INET6` (AAAA) record.
+fn parse_address(address: *const AvahiAddress) -> Option<(ServiceProtocol, String)> {
     if address.is_null() {
         None
     } else {
         let address_vector = Vec::with_capacity(AVAHI_ADDRESS_STR_MAX).as_ptr();
         unsafe { avahi_address_snprint(address_vector, AVAHI_ADDRESS_STR_MAX, address) };
 
-        parse_c_string(address_vector)
+        let protocol = unsafe { (*address).proto };
+
+        parse_c_string(address_vector).map(|parsed| (to_service_protocol(protocol), parsed))
     }
 }
 
@@ -45,6 +71,46 @@ fn parse_txt(txt: *mut AvahiStringList) -> Option<String> {
     }
 }
 
+/// Walks `txt` node-by-node with `avahi_string_list_get_pair`, yielding a
+/// lookup table of individual properties. Values are kept as raw bytes
+/// since TXT record values aren't guaranteed to be valid UTF-8, and a key
+/// with no `=value` is stored with an empty value.
+fn parse_txt_records(txt: *mut AvahiStringList) -> HashMap<String, Vec<u8>> {
+    let mut records = HashMap::new();
+    let mut node = txt;
+
+    while !node.is_null() {
+        let mut key_ptr: *mut c_char = ptr::null_mut();
+        let mut value_ptr: *mut c_char = ptr::null_mut();
+        let mut value_size: size_t = 0;
+
+        let result_code = unsafe {
+            avahi_string_list_get_pair(node, &mut key_ptr, &mut value_ptr, &mut value_size)
+        };
+
+        if result_code == 0 {
+            if let Some(key) = parse_c_string(key_ptr) {
+                let value = if value_ptr.is_null() {
+                    Vec::new()
+                } else {
+                    unsafe { slice::from_raw_parts(value_ptr as *const u8, value_size).to_vec() }
+                };
+
+                records.insert(key, value);
+            }
+
+            unsafe {
+                avahi_free(key_ptr as *mut c_void);
+                avahi_free(value_ptr as *mut c_void);
+            }
+        }
+
+        node = unsafe { avahi_string_list_get_next(node) };
+    }
+
+    records
+}
+
 #[derive(Debug)]
 struct BrowseCallbackParameters {
     event: AvahiBrowserEvent,
@@ -60,6 +126,7 @@ struct BrowseCallbackParameters {
 struct ResolveCallbackParameters {
     event: AvahiResolverEvent,
     address: Option<String>,
+    address_protocol: Option<ServiceProtocol>,
     interface: i32,
     port: u16,
     protocol: i32,
@@ -68,9 +135,15 @@ struct ResolveCallbackParameters {
     domain: Option<String>,
     host_name: Option<String>,
     txt: Option<String>,
+    txt_records: HashMap<String, Vec<u8>>,
     flags: AvahiLookupResultFlags,
 }
 
+#[derive(Debug)]
+struct EntryGroupCallbackParameters {
+    state: AvahiEntryGroupState,
+}
+
 #[allow(unused_variables)]
 extern "C" fn client_callback(s: *mut AvahiClient,
                               state: AvahiClientState,
@@ -125,9 +198,15 @@ extern "C" fn resolve_callback(r: *mut AvahiServiceResolver,
         mem::transmute::<*mut c_void, &Sender<ResolveCallbackParameters>>(userdata)
     };
 
+    let (resolved_address, resolved_protocol) = match parse_address(address) {
+        Some((protocol, address)) => (Some(address), Some(protocol)),
+        None => (None, None),
+    };
+
     let parameters = ResolveCallbackParameters {
         event: event,
-        address: parse_address(address),
+        address: resolved_address,
+        address_protocol: resolved_protocol,
         interface: interface,
         protocol: protocol,
         port: port,
@@ -136,16 +215,34 @@ extern "C" fn resolve_callback(r: *mut AvahiServiceResolver,
         service_type: parse_c_string(service_type),
         domain: parse_c_string(domain),
         txt: parse_txt(txt),
+        txt_records: parse_txt_records(txt),
         flags: flags,
     };
 
     sender.send(parameters).unwrap();
 }
 
+#[allow(unused_variables)]
+extern "C" fn entry_group_callback(g: *mut AvahiEntryGroup,
+                                   state: AvahiEntryGroupState,
+                                   userdata: *mut c_void) {
+
+    let sender = unsafe {
+        mem::transmute::<*mut c_void, &Sender<EntryGroupCallbackParameters>>(userdata)
+    };
+
+    // The entry group outlives `publish_service`, so once its caller has
+    // moved on (e.g. after `ESTABLISHED`) the receiving end may already be
+    // gone; a late state change is then a benign disconnect, not a crash.
+    let _ = sender.send(EntryGroupCallbackParameters { state: state });
+}
+
 pub struct AvahiWrapper {
     client: RefCell<Option<*mut AvahiClient>>,
     poll: RefCell<Option<*mut AvahiThreadedPoll>>,
     service_browser: RefCell<Option<*mut AvahiServiceBrowser>>,
+    entry_group: RefCell<Option<*mut AvahiEntryGroup>>,
+    entry_group_sender: RefCell<Option<Sender<EntryGroupCallbackParameters>>>,
 }
 
 impl AvahiWrapper {
@@ -154,10 +251,15 @@ impl AvahiWrapper {
             client: RefCell::new(None),
             poll: RefCell::new(None),
             service_browser: RefCell::new(None),
+            entry_group: RefCell::new(None),
+            entry_group_sender: RefCell::new(None),
         }
     }
 
-    pub fn start_browser<T: DiscoveryListener>(&self, service_type: &str, listener: T) {
+    pub fn start_browser<T: DiscoveryListener>(&self,
+                                               service_type: &str,
+                                               protocol: AvahiProtocol,
+                                               listener: T) {
         self.initialize_poll();
         self.initialize_client();
 
@@ -170,7 +272,7 @@ impl AvahiWrapper {
         let avahi_service_browser = unsafe {
             avahi_service_browser_new(self.client.borrow().unwrap(),
                                       AvahiIfIndex::AVAHI_IF_UNSPEC,
-                                      AvahiProtocol::AVAHI_PROTO_UNSPEC,
+                                      protocol,
                                       CString::new(service_type).unwrap().as_ptr(),
                                       ptr::null_mut(),
                                       AvahiLookupFlags::AVAHI_LOOKUP_UNSPEC,
@@ -192,8 +294,9 @@ impl AvahiWrapper {
                         interface: a.interface,
                         name: &a.name.unwrap(),
                         port: 0,
-                        protocol: a.protocol,
+                        protocol: to_service_protocol(a.protocol),
                         txt: &"",
+                        txt_records: HashMap::new(),
                         type_name: service_type,
                     };
 
@@ -203,16 +306,48 @@ impl AvahiWrapper {
 
                     // self.resolve(service);
                 }
+                AvahiBrowserEvent::AVAHI_BROWSER_REMOVE => {
+                    let service = ServiceDescription {
+                        address: &"",
+                        domain: &a.domain.unwrap(),
+                        host_name: &"",
+                        interface: a.interface,
+                        name: &a.name.unwrap(),
+                        port: 0,
+                        protocol: to_service_protocol(a.protocol),
+                        txt: &"",
+                        txt_records: HashMap::new(),
+                        type_name: service_type,
+                    };
+
+                    listener.on_service_removed(service);
+                }
+                AvahiBrowserEvent::AVAHI_BROWSER_CACHE_EXHAUSTED => {
+                    debug!("Avahi browser cache exhausted.");
+                }
                 AvahiBrowserEvent::AVAHI_BROWSER_ALL_FOR_NOW => {
                     listener.on_all_discovered();
                     break;
                 }
+                AvahiBrowserEvent::AVAHI_BROWSER_FAILURE => {
+                    let error_string = unsafe {
+                        CStr::from_ptr(avahi_strerror(avahi_client_errno(self.client
+                            .borrow()
+                            .unwrap())))
+                    };
+
+                    listener.on_discovery_failed(error_string.to_str().unwrap().to_owned());
+                    break;
+                }
                 _ => println!("Default {:?}", a.event),
             }
         }
     }
 
-    pub fn resolve<T: ResolveListener>(&self, service: ServiceDescription, listener: T) {
+    pub fn resolve<T: ResolveListener>(&self,
+                                       service: ServiceDescription,
+                                       protocol: AvahiProtocol,
+                                       listener: T) {
         let (tx, rx) = channel::<ResolveCallbackParameters>();
 
         let userdata = unsafe {
@@ -222,11 +357,11 @@ impl AvahiWrapper {
         let avahi_service_resolver = unsafe {
             avahi_service_resolver_new(self.client.borrow().unwrap(),
                                        service.interface,
-                                       service.protocol,
+                                       to_avahi_protocol(service.protocol),
                                        CString::new(service.name).unwrap().as_ptr(),
                                        CString::new(service.type_name).unwrap().as_ptr(),
                                        CString::new(service.domain).unwrap().as_ptr(),
-                                       AvahiProtocol::AVAHI_PROTO_UNSPEC,
+                                       protocol,
                                        AvahiLookupFlags::AVAHI_LOOKUP_UNSPEC,
                                        *Box::new(resolve_callback),
                                        userdata)
@@ -236,24 +371,192 @@ impl AvahiWrapper {
 
         let raw_service = rx.recv().unwrap();
 
-        let service = ServiceDescription {
-            address: &raw_service.address.unwrap(),
-            domain: &raw_service.domain.unwrap(),
-            host_name: &raw_service.host_name.unwrap(),
-            interface: raw_service.interface,
-            name: &raw_service.name.unwrap(),
-            port: raw_service.port,
-            protocol: raw_service.protocol,
-            txt: &raw_service.txt.unwrap(),
-            type_name: &raw_service.service_type.unwrap(),
+        match raw_service.event {
+            AvahiResolverEvent::AVAHI_RESOLVER_FOUND => {
+                let service = ServiceDescription {
+                    address: &raw_service.address.unwrap(),
+                    domain: &raw_service.domain.unwrap(),
+                    host_name: &raw_service.host_name.unwrap(),
+                    interface: raw_service.interface,
+                    name: &raw_service.name.unwrap(),
+                    port: raw_service.port,
+                    protocol: raw_service.address_protocol
+                        .unwrap_or_else(|| to_service_protocol(raw_service.protocol)),
+                    txt: &raw_service.txt.unwrap_or_default(),
+                    txt_records: raw_service.txt_records,
+                    type_name: &raw_service.service_type.unwrap(),
+                };
+
+                listener.on_service_resolved(service);
+            }
+            _ => {
+                let error_string = unsafe {
+                    CStr::from_ptr(avahi_strerror(avahi_client_errno(self.client
+                        .borrow()
+                        .unwrap())))
+                };
+
+                listener.on_resolve_failed(error_string.to_str().unwrap().to_owned());
+            }
+        }
+
+        unsafe {
+            avahi_service_resolver_free(avahi_service_resolver);
+        }
+    }
+
+    /// Publishes `service` under its own name, retrying with a mangled name
+    /// if the name collides with a service already on the network.
+    ///
+    /// Blocks until the entry group reaches `AVAHI_ENTRY_GROUP_ESTABLISHED`
+    /// or `AVAHI_ENTRY_GROUP_FAILURE`, reporting the outcome to `listener`.
+    pub fn publish_service<T: PublishListener>(&self, service: ServiceDescription, listener: T) {
+        self.initialize_poll();
+        self.initialize_client();
+
+        let (tx, rx) = channel::<EntryGroupCallbackParameters>();
+
+        // `entry_group_callback` may still fire after this function returns
+        // (e.g. a collision with a service announced after we've already
+        // been established), so the sender has to outlive this call rather
+        // than live on this stack frame — store it alongside the group.
+        *self.entry_group_sender.borrow_mut() = Some(tx);
+
+        let userdata = unsafe {
+            mem::transmute::<&Sender<EntryGroupCallbackParameters>, *mut c_void>(
+                self.entry_group_sender.borrow().as_ref().unwrap())
         };
 
-        listener.on_service_resolved(service);
+        let avahi_entry_group = unsafe {
+            avahi_entry_group_new(self.client.borrow().unwrap(),
+                                  *Box::new(entry_group_callback),
+                                  userdata)
+        };
 
-        // println!("Resolved {:?}", rx.recv().unwrap());
+        if avahi_entry_group.is_null() {
+            panic!("Failed to create avahi entry group!");
+        }
 
-        unsafe {
-            avahi_service_resolver_free(avahi_service_resolver);
+        *self.entry_group.borrow_mut() = Some(avahi_entry_group);
+
+        let mut current_name = service.name.to_owned();
+        let mut collision_count = 0u32;
+
+        self.add_service(&service, &current_name);
+
+        self.start_polling();
+
+        for parameters in rx.iter() {
+            match parameters.state {
+                AvahiEntryGroupState::AVAHI_ENTRY_GROUP_ESTABLISHED => {
+                    listener.on_service_registered(current_name.clone());
+                    break;
+                }
+                AvahiEntryGroupState::AVAHI_ENTRY_GROUP_FAILURE => {
+                    let error_string = unsafe {
+                        CStr::from_ptr(avahi_strerror(avahi_client_errno(self.client
+                            .borrow()
+                            .unwrap())))
+                    };
+
+                    listener.on_registration_failed(error_string.to_str().unwrap().to_owned());
+                    break;
+                }
+                AvahiEntryGroupState::AVAHI_ENTRY_GROUP_COLLISION => {
+                    collision_count += 1;
+                    current_name = AvahiWrapper::next_alternative_name(&current_name, collision_count);
+
+                    warn!("Service name collision, retrying as '{}'.", current_name);
+
+                    unsafe { avahi_entry_group_reset(avahi_entry_group) };
+
+                    self.add_service(&service, &current_name);
+                }
+                AvahiEntryGroupState::AVAHI_ENTRY_GROUP_UNCOMMITED |
+                AvahiEntryGroupState::AVAHI_ENTRY_GROUP_REGISTERING => {}
+                _ => {}
+            }
+        }
+    }
+
+    /// Derives the next candidate name to retry a collided publish under.
+    ///
+    /// `collision_count` is the number of collisions seen so far for this
+    /// publish (the caller increments it before calling), so the original
+    /// name is only ever mangled on the first collision ("{name}-2");
+    /// subsequent collisions replace the "-{collision_count}" suffix added
+    /// by the previous one with the next number ("-2" -> "-3", ...).
+    /// Tracking the count explicitly (rather than sniffing the name for a
+    /// hyphen) keeps this correct for services whose original name already
+    /// contains one.
+    fn next_alternative_name(current_name: &str, collision_count: u32) -> String {
+        if collision_count <= 1 {
+            return format!("{}-2", current_name);
+        }
+
+        let previous_suffix = format!("-{}", collision_count);
+        let base_name = if current_name.ends_with(&previous_suffix) {
+            &current_name[..current_name.len() - previous_suffix.len()]
+        } else {
+            current_name
+        };
+
+        format!("{}-{}", base_name, collision_count + 1)
+    }
+
+    /// Adds `service` to the current entry group under `name` and commits
+    /// it. Only touches the group while it is empty, so a re-commit after
+    /// `avahi_entry_group_reset` doesn't duplicate the entry.
+    fn add_service(&self, service: &ServiceDescription, name: &str) {
+        let entry_group = self.entry_group.borrow().unwrap();
+
+        let is_empty = unsafe { avahi_entry_group_is_empty(entry_group) } != 0;
+
+        if !is_empty {
+            return;
+        }
+
+        // Build the TXT record list from the structured key/value map rather
+        // than the joined string, one `avahi_string_list_add_pair_arbitrary`
+        // call per entry. Values are arbitrary bytes (chunk0-3), so they're
+        // passed as a length-prefixed buffer rather than through `CString`,
+        // which would panic on an interior NUL and truncate at one.
+        let mut txt_list: *mut AvahiStringList = ptr::null_mut();
+
+        for (key, value) in &service.txt_records {
+            let c_key = CString::new(key.as_str()).unwrap();
+
+            txt_list = unsafe {
+                avahi_string_list_add_pair_arbitrary(txt_list,
+                                                     c_key.as_ptr(),
+                                                     value.as_ptr(),
+                                                     value.len() as size_t)
+            };
+        }
+
+        let result_code = unsafe {
+            avahi_entry_group_add_service_strlst(entry_group,
+                                                 service.interface,
+                                                 to_avahi_protocol(service.protocol),
+                                                 AvahiPublishFlags::AVAHI_PUBLISH_UNSPEC,
+                                                 CString::new(name).unwrap().as_ptr(),
+                                                 CString::new(service.type_name).unwrap().as_ptr(),
+                                                 CString::new(service.domain).unwrap().as_ptr(),
+                                                 ptr::null(),
+                                                 service.port,
+                                                 txt_list)
+        };
+
+        unsafe { avahi_string_list_free(txt_list) };
+
+        if result_code < 0 {
+            panic!("Failed to add service to entry group: {}", result_code);
+        }
+
+        let commit_result = unsafe { avahi_entry_group_commit(entry_group) };
+
+        if commit_result < 0 {
+            panic!("Failed to commit entry group: {}", commit_result);
         }
     }
 
@@ -310,4 +613,14 @@ impl AvahiWrapper {
     }
 
     fn on_service_discovered(&self, parameters: BrowseCallbackParameters) {}
+}
+
+impl Drop for AvahiWrapper {
+    fn drop(&mut self) {
+        if let Some(entry_group) = self.entry_group.borrow_mut().take() {
+            unsafe {
+                avahi_entry_group_free(entry_group);
+            }
+        }
+    }
 }
\ No newline at end of file